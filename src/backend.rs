@@ -0,0 +1,67 @@
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+use crate::Result;
+
+/// The byte store a `KvLogStore` appends records to and scans for replay.
+/// `Disk` backs a persistent on-disk log; `Memory` backs an ephemeral,
+/// growable in-memory log created via `KvStore::new_in_memory`. Both sides
+/// implement `Read + Write + Seek`, so `KvLogStore`'s framing, compaction
+/// and log-pointer logic works unchanged against either.
+pub enum LogBackend {
+    Disk(File),
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl LogBackend {
+    pub fn len(&self) -> Result<u64> {
+        match self {
+            LogBackend::Disk(file) => Ok(file.metadata()?.len()),
+            LogBackend::Memory(cursor) => Ok(cursor.get_ref().len() as u64),
+        }
+    }
+
+    pub fn set_len(&mut self, len: u64) -> Result<()> {
+        match self {
+            LogBackend::Disk(file) => Ok(file.set_len(len)?),
+            LogBackend::Memory(cursor) => {
+                cursor.get_mut().truncate(len as usize);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Read for LogBackend {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            LogBackend::Disk(file) => file.read(buf),
+            LogBackend::Memory(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+impl Write for LogBackend {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            LogBackend::Disk(file) => file.write(buf),
+            LogBackend::Memory(cursor) => cursor.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            LogBackend::Disk(file) => file.flush(),
+            LogBackend::Memory(cursor) => cursor.flush(),
+        }
+    }
+}
+
+impl Seek for LogBackend {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            LogBackend::Disk(file) => file.seek(pos),
+            LogBackend::Memory(cursor) => cursor.seek(pos),
+        }
+    }
+}
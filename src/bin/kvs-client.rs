@@ -0,0 +1,32 @@
+use kvs::{KvCli, Operations, Response, Result};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about, author)]
+struct Args {
+    /// Operations that can be performed on the KvStore
+    #[structopt(subcommand)]
+    pub ops: Operations,
+
+    /// Address of the kvs-server to connect to
+    #[structopt(short, long, default_value = "127.0.0.1:4000")]
+    pub addr: String,
+}
+
+fn main() -> Result<()> {
+    let args = Args::from_args();
+    let mut cli = KvCli::connect(&args.addr)?;
+
+    match cli.send(&args.ops)? {
+        Response::Ok(Some(value)) => println!("{}", value),
+        Response::Ok(None) => match args.ops {
+            Operations::Get { .. } => println!("Key not found"),
+            _ => {}
+        },
+        Response::Err(message) => {
+            println!("{}", message);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use kvs::{net, KvStore, Operations, Response, Result};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about, author)]
+struct Args {
+    /// Address to listen on, e.g. 127.0.0.1:4000
+    #[structopt(short, long, default_value = "127.0.0.1:4000")]
+    pub addr: String,
+
+    /// Directory where the KvStore's log files are kept
+    #[structopt(short, long, parse(from_os_str), default_value = ".")]
+    pub log_store: PathBuf,
+}
+
+fn handle_client(stream: TcpStream, kvs: Arc<Mutex<KvStore<String, String>>>) -> Result<()> {
+    let mut reader = stream.try_clone()?;
+    let mut writer = stream;
+    loop {
+        let op: Operations = match net::recv_message(&mut reader) {
+            Ok(op) => op,
+            Err(_) => return Ok(()),
+        };
+
+        let response = {
+            let mut kvs = kvs.lock().unwrap();
+            match op {
+                Operations::Set { key, value } => match kvs.set(key, value) {
+                    Ok(()) => Response::Ok(None),
+                    Err(e) => Response::Err(e.to_string()),
+                },
+                Operations::Get { key } => match kvs.get(key) {
+                    Ok(value) => Response::Ok(value),
+                    Err(e) => Response::Err(e.to_string()),
+                },
+                Operations::Rm { key } => match kvs.remove(key) {
+                    Ok(()) => Response::Ok(None),
+                    Err(_) => Response::Err("Key not found".to_owned()),
+                },
+                Operations::Upgrade => Response::Err(
+                    "Upgrade is a local-only operation; run `kvs upgrade` directly".to_owned(),
+                ),
+            }
+        };
+
+        net::send_message(&mut writer, &response)?;
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::from_args();
+    let kvs: KvStore<String, String> = KvStore::open(args.log_store)?;
+    let kvs = Arc::new(Mutex::new(kvs));
+
+    let listener = TcpListener::bind(&args.addr)?;
+    eprintln!("kvs-server listening on {}", args.addr);
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let kvs = Arc::clone(&kvs);
+        thread::spawn(move || {
+            if let Err(e) = handle_client(stream, kvs) {
+                eprintln!("Error handling client: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
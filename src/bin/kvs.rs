@@ -12,26 +12,21 @@ struct Args {
     /// Path where the KvStore.log file is present
     #[structopt(short, long, parse(from_os_str), default_value = ".")]
     pub log_store: PathBuf,
-
-    /// Quick mode. set: faster. get: no change. rm: faster, no reporting.
-    #[structopt(short, long)]
-    pub quick: bool,
 }
 
 fn main() -> Result<()> {
     let args = Args::from_args();
-    let mut kvs = if args.quick {
-        KvStore::quick_open(args.log_store)?
-    } else {
-        KvStore::open(args.log_store)?
-    };
 
     match args.ops {
+        Operations::Upgrade => {
+            KvStore::<String, String>::upgrade(&args.log_store)?;
+        }
         Operations::Set { key, value } => {
+            let mut kvs: KvStore<String, String> = KvStore::open(&args.log_store)?;
             kvs.set(key, value)?;
         }
         Operations::Get { key } => {
-            kvs.build_map()?;
+            let mut kvs: KvStore<String, String> = KvStore::open(&args.log_store)?;
             if let Some(value) = kvs.get(key)? {
                 println!("{}", value);
             } else {
@@ -39,6 +34,7 @@ fn main() -> Result<()> {
             }
         }
         Operations::Rm { key } => {
+            let mut kvs: KvStore<String, String> = KvStore::open(&args.log_store)?;
             if kvs.remove(key).is_err() {
                 println!("Key not found");
                 std::process::exit(1);
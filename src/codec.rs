@@ -0,0 +1,116 @@
+use crate::kvls::{DeLogEntry, SeLogEntry};
+use crate::Result;
+use failure::err_msg;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Identifies which `Codec` a log was written with, so it can be persisted
+/// alongside the log and the right decoder picked automatically on reopen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecId {
+    Json,
+    Bincode,
+    Cbor,
+}
+
+impl CodecId {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            CodecId::Json => 0,
+            CodecId::Bincode => 1,
+            CodecId::Cbor => 2,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Result<CodecId> {
+        match byte {
+            0 => Ok(CodecId::Json),
+            1 => Ok(CodecId::Bincode),
+            2 => Ok(CodecId::Cbor),
+            other => Err(err_msg(format!("Unknown codec id: {}", other))),
+        }
+    }
+
+    pub(crate) fn codec<K, V>(self) -> Box<dyn Codec<K, V> + Send>
+    where
+        K: Serialize + DeserializeOwned + 'static,
+        V: Serialize + DeserializeOwned + 'static,
+    {
+        match self {
+            CodecId::Json => Box::new(JsonCodec),
+            CodecId::Bincode => Box::new(BincodeCodec),
+            CodecId::Cbor => Box::new(CborCodec),
+        }
+    }
+}
+
+/// Encodes/decodes the on-disk payload of a log entry. The framed header
+/// written around each record (length + CRC) is codec-agnostic, so a codec
+/// only has to turn a `SeLogEntry<K, V>` into bytes and back. `payload` is
+/// already sliced to its exact length by the framing, so decoding it never
+/// needs to report how much it consumed.
+pub(crate) trait Codec<K, V> {
+    fn id(&self) -> CodecId;
+    fn encode(&self, entry: &SeLogEntry<K, V>) -> Result<Vec<u8>>;
+    fn decode(&self, payload: &[u8]) -> Result<DeLogEntry<K, V>>;
+}
+
+pub struct JsonCodec;
+
+impl<K, V> Codec<K, V> for JsonCodec
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    fn id(&self) -> CodecId {
+        CodecId::Json
+    }
+
+    fn encode(&self, entry: &SeLogEntry<K, V>) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(entry)?)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<DeLogEntry<K, V>> {
+        Ok(serde_json::from_slice(payload)?)
+    }
+}
+
+pub struct BincodeCodec;
+
+impl<K, V> Codec<K, V> for BincodeCodec
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    fn id(&self) -> CodecId {
+        CodecId::Bincode
+    }
+
+    fn encode(&self, entry: &SeLogEntry<K, V>) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(entry)?)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<DeLogEntry<K, V>> {
+        Ok(bincode::deserialize(payload)?)
+    }
+}
+
+pub struct CborCodec;
+
+impl<K, V> Codec<K, V> for CborCodec
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    fn id(&self) -> CodecId {
+        CodecId::Cbor
+    }
+
+    fn encode(&self, entry: &SeLogEntry<K, V>) -> Result<Vec<u8>> {
+        Ok(serde_cbor::to_vec(entry)?)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<DeLogEntry<K, V>> {
+        Ok(serde_cbor::from_slice(payload)?)
+    }
+}
@@ -1,22 +1,24 @@
-use crate::Result;
-use std::fs::{File, OpenOptions};
-use std::path::Path;
+use crate::net::{recv_message, send_message};
+use crate::{Operations, Response, Result};
+use std::net::{TcpStream, ToSocketAddrs};
 
+/// A `KvCli` is the client side of a `kvs-server` connection: it sends
+/// `Operations` requests over TCP and reads back the framed `Response`.
 pub struct KvCli {
-    log_handle: File,
+    stream: TcpStream,
 }
 
 impl KvCli {
-    pub fn new<F>(filename: F) -> Result<KvCli>
-    where
-        F: AsRef<Path>,
-    {
+    /// Connects to a running `kvs-server` at `addr`.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<KvCli> {
         Ok(KvCli {
-            log_handle: OpenOptions::new()
-                .read(true)
-                .append(true)
-                .create(true)
-                .open(filename)?,
+            stream: TcpStream::connect(addr)?,
         })
     }
+
+    /// Sends `op` to the server and waits for its response.
+    pub fn send(&mut self, op: &Operations) -> Result<Response> {
+        send_message(&mut self.stream, op)?;
+        recv_message(&mut self.stream)
+    }
 }
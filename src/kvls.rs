@@ -1,38 +1,129 @@
+use crate::backend::LogBackend;
+use crate::codec::{Codec, CodecId};
 use crate::Result;
+use crc::crc32;
 use failure::err_msg;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::convert::TryInto;
 use std::ffi::OsStr;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hash;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
-pub struct KvLogStore {
-    path: PathBuf,
-    reader: BufReader<File>,
-    writer: BufWriter<File>,
+/// Size in bytes of the `{ payload_len: u32, crc32: u32 }` header written
+/// before every entry in a segment.
+const RECORD_HEADER_LEN: u64 = 8;
+
+/// Once the active segment grows past this many bytes, it is closed
+/// (becoming immutable) and a fresh active segment is opened.
+const SEGMENT_SIZE_THRESHOLD: u64 = 1024 * 1024;
+
+/// A merge of the closed segments runs once at least this fraction of
+/// their records are dead (superseded or removed).
+const MERGE_RATIO: f64 = 0.5;
+
+/// Identifies a `kvls.codec` file as belonging to this crate, so a
+/// misversioned or unrelated file is reported rather than misread.
+const FORMAT_MAGIC: [u8; 4] = *b"KVLS";
+/// The on-disk format this build reads and writes: a segmented log with a
+/// `{ magic, version, codec_id }` header. Bump this whenever the segment
+/// or entry layout changes, and teach `KvLogStore::upgrade` to migrate
+/// from the previous value.
+const FORMAT_VERSION: u8 = 1;
+/// Stores that predate format versioning wrote a single unframed codec id
+/// byte here with no magic or version. `read_header` reports those as
+/// version 0.
+const LEGACY_FORMAT_VERSION: u8 = 0;
+const LEGACY_LOG_FILE_NAME: &str = "kvls.ser";
+
+const HEADER_FILE_NAME: &str = "kvls.codec";
+const INDEX_FILE_NAME: &str = "kvls.index";
+/// Records which `kvls.<id>.seg` is the active one. Merge output is always
+/// assigned a fresh, higher id than whatever was active when the merge
+/// started, so the highest id on disk is not reliably the active segment
+/// after a merge; this marker is the source of truth instead.
+const ACTIVE_FILE_NAME: &str = "kvls.active";
+const SEGMENT_FILE_EXT: &str = "seg";
+
+/// A single bitcask-style log segment: either the active segment being
+/// appended to, or a closed, immutable one awaiting merge.
+struct Segment {
+    id: u32,
+    backend: LogBackend,
+    /// Total records ever written to this segment, live or dead. Used by
+    /// `merge_analysis` to estimate reclaimable space.
     num_entries: usize,
-    max_entries: usize,
+}
+
+pub struct KvLogStore<K, V> {
+    /// `None` for an in-memory store, which has no directory to keep
+    /// segment files, a codec header, or an index snapshot in.
+    path: Option<PathBuf>,
+    /// Closed segments, ascending by id, never written to again until a
+    /// merge replaces the whole list.
+    segments: Vec<Segment>,
+    /// The segment all writes currently land on.
+    active: Segment,
+    next_segment_id: u32,
+    codec: Box<dyn Codec<K, V> + Send>,
+    segment_size_threshold: u64,
+    merge_ratio: f64,
 }
 
 #[derive(Serialize)]
-struct SeLogEntry<'a> {
-    key: &'a str,
-    value: Option<&'a str>,
+pub(crate) struct SeLogEntry<'a, K, V> {
+    pub(crate) key: &'a K,
+    pub(crate) value: Option<&'a V>,
 }
 
 #[derive(Deserialize)]
-struct DeLogEntry {
-    key: String,
-    value: Option<String>,
+pub(crate) struct DeLogEntry<K, V> {
+    pub(crate) key: K,
+    pub(crate) value: Option<V>,
 }
 
-const LOG_FILE_NAME: &str = "kvls.ser";
-const COMPACTION_FILE: &str = "kvls.compact.ser";
+/// Snapshot of `kvmap` plus enough segment bookkeeping to validate and
+/// reuse it without a full replay. See `load_or_build_map`.
+#[derive(Serialize, Deserialize)]
+struct IndexSnapshot<K: Eq + Hash> {
+    active_id: u32,
+    active_len: u64,
+    active_entries: usize,
+    closed_ids: Vec<u32>,
+    segment_entry_counts: Vec<(u32, usize)>,
+    map: HashMap<K, (u32, u64)>,
+}
+
+impl<K, V> KvLogStore<K, V>
+where
+    K: Eq + Hash + Debug + Clone + Serialize + DeserializeOwned + 'static,
+    V: Serialize + DeserializeOwned + 'static,
+{
+    /// Method to open a Key Value Store from a directory, defaulting to the JSON
+    /// codec for newly created stores.
+    pub fn new<F>(path: F) -> Result<KvLogStore<K, V>>
+    where
+        F: AsRef<Path> + AsRef<OsStr> + Clone,
+    {
+        Self::new_with(path, CodecId::Json)
+    }
 
-impl KvLogStore {
-    /// Method to open a Key Value Store from a file
-    pub fn new<F>(path: F) -> Result<KvLogStore>
+    /// Method to open a Key Value Store from a directory with a preferred codec.
+    /// If the directory already has a log, the format header (recorded in
+    /// `kvls.codec`) takes precedence: its codec id picks the right
+    /// decoder, and its version must match this build's `FORMAT_VERSION`
+    /// or the open is refused with a message pointing at `kvs upgrade`. A
+    /// directory with no header but a stray `kvls.ser` predates the header
+    /// file itself (the pre-chunk0-2 format, implicitly JSON) and is
+    /// treated the same way rather than silently adopted as brand new.
+    /// Existing `kvls.<id>.seg` segment files are discovered and reopened;
+    /// the `kvls.active` marker picks which one is active, falling back to
+    /// the highest id if the marker is missing or stale.
+    pub fn new_with<F>(path: F, codec: CodecId) -> Result<KvLogStore<K, V>>
     where
         F: AsRef<Path> + AsRef<OsStr> + Clone,
     {
@@ -41,150 +132,792 @@ impl KvLogStore {
             return Err(err_msg("Error processing path"));
         }
 
-        let (reader, writer) = Self::open_file_handles(&path, LOG_FILE_NAME)?;
+        let header = match Self::read_header(&path)? {
+            None if path.join(LEGACY_LOG_FILE_NAME).exists() => {
+                // No header file at all, but a log is already there: this
+                // predates the header file itself rather than being a new
+                // store. Write the one-byte legacy header so `read_header`
+                // (and `upgrade`) can see it, instead of letting it fall
+                // through to "brand new" and get silently orphaned.
+                fs::write(path.join(HEADER_FILE_NAME), [CodecId::Json.to_byte()])?;
+                Some((LEGACY_FORMAT_VERSION, CodecId::Json))
+            }
+            other => other,
+        };
 
-        Ok(KvLogStore {
-            path,
-            reader,
-            writer,
+        let codec_id = match header {
+            None => {
+                Self::write_header(&path, codec)?;
+                codec
+            }
+            Some((version, codec_id)) if version == FORMAT_VERSION => codec_id,
+            Some((version, _)) => {
+                return Err(err_msg(format!(
+                    "Store at {} is format version {} but this build needs version {}; \
+                     run `kvs upgrade` first",
+                    path.display(),
+                    version,
+                    FORMAT_VERSION
+                )));
+            }
+        };
+
+        let mut ids = Self::discover_segment_ids(&path)?;
+        ids.sort_unstable();
+        let max_id = ids.last().copied().unwrap_or(0);
+
+        let active_id = match Self::read_active_marker(&path)? {
+            Some(id) if ids.contains(&id) => id,
+            _ => max_id,
+        };
+        ids.retain(|&id| id != active_id);
+        Self::write_active_marker(&path, active_id)?;
+
+        let mut segments = Vec::with_capacity(ids.len());
+        for id in ids {
+            let file = Self::open_disk_file(&path, &Self::segment_filename(id))?;
+            segments.push(Segment {
+                id,
+                backend: LogBackend::Disk(file),
+                num_entries: 0,
+            });
+        }
+
+        let active_file = Self::open_disk_file(&path, &Self::segment_filename(active_id))?;
+        let active = Segment {
+            id: active_id,
+            backend: LogBackend::Disk(active_file),
             num_entries: 0,
-            max_entries: 1024,
+        };
+
+        Ok(KvLogStore {
+            path: Some(path),
+            segments,
+            active,
+            next_segment_id: max_id + 1,
+            codec: codec_id.codec(),
+            segment_size_threshold: SEGMENT_SIZE_THRESHOLD,
+            merge_ratio: MERGE_RATIO,
         })
     }
 
-    fn open_file_handles<F>(path: F, file: &str) -> Result<(BufReader<File>, BufWriter<File>)>
+    /// Keeps the log in growable in-memory buffers instead of on disk, for
+    /// fast, isolated tests or embedding the store where persistence isn't
+    /// wanted. There is no sidecar codec/index file to manage, so this can
+    /// never fail.
+    pub fn new_in_memory() -> KvLogStore<K, V> {
+        Self::new_in_memory_with(CodecId::Json)
+    }
+
+    pub fn new_in_memory_with(codec: CodecId) -> KvLogStore<K, V> {
+        KvLogStore {
+            path: None,
+            segments: Vec::new(),
+            active: Segment {
+                id: 0,
+                backend: LogBackend::Memory(Cursor::new(Vec::new())),
+                num_entries: 0,
+            },
+            next_segment_id: 1,
+            codec: codec.codec(),
+            segment_size_threshold: SEGMENT_SIZE_THRESHOLD,
+            merge_ratio: MERGE_RATIO,
+        }
+    }
+
+    fn segment_filename(id: u32) -> String {
+        format!("kvls.{}.{}", id, SEGMENT_FILE_EXT)
+    }
+
+    /// Scans `path` for existing `kvls.<id>.seg` segment files.
+    fn discover_segment_ids(path: &Path) -> Result<Vec<u32>> {
+        let suffix = format!(".{}", SEGMENT_FILE_EXT);
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if let Some(id) = name
+                .strip_prefix("kvls.")
+                .and_then(|rest| rest.strip_suffix(&suffix))
+                .and_then(|id| id.parse::<u32>().ok())
+            {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    fn open_disk_file<F>(path: F, file: &str) -> Result<File>
     where
         F: AsRef<Path> + AsRef<OsStr>,
     {
         let filename = Path::new(&path).join(file);
-        let log_handle = OpenOptions::new()
+        Ok(OpenOptions::new()
             .create(true)
+            .read(true)
             .append(true)
-            .open(filename.clone())?;
+            .open(filename)?)
+    }
 
-        let writer = BufWriter::new(log_handle);
-        let reader = BufReader::new(File::open(filename)?);
+    /// Reads the `kvls.codec` format header, if one exists yet. A file of
+    /// exactly one byte predates format versioning and is reported as
+    /// `LEGACY_FORMAT_VERSION` with that byte as the codec id; anything
+    /// else must be `{ magic: 4, version: 1, codec_id: 1 }`.
+    fn read_header(path: &Path) -> Result<Option<(u8, CodecId)>> {
+        let header_file = path.join(HEADER_FILE_NAME);
+        if !header_file.exists() {
+            return Ok(None);
+        }
+        let bytes = fs::read(&header_file)?;
+        if bytes.len() == 1 {
+            return Ok(Some((LEGACY_FORMAT_VERSION, CodecId::from_byte(bytes[0])?)));
+        }
+        if bytes.len() != 6 || bytes[0..4] != FORMAT_MAGIC {
+            return Err(err_msg("Unrecognized kvls.codec header"));
+        }
+        Ok(Some((bytes[4], CodecId::from_byte(bytes[5])?)))
+    }
 
-        Ok((reader, writer))
+    /// Reads the `kvls.active` marker, if one has been written yet.
+    fn read_active_marker(path: &Path) -> Result<Option<u32>> {
+        let marker_file = path.join(ACTIVE_FILE_NAME);
+        if !marker_file.exists() {
+            return Ok(None);
+        }
+        let text = fs::read_to_string(marker_file)?;
+        Ok(Some(
+            text.trim()
+                .parse()
+                .map_err(|_| err_msg("Corrupt kvls.active marker"))?,
+        ))
     }
 
-    fn commit_operation(op: SeLogEntry, mut writer: impl Write + Seek) -> Result<u64> {
-        let v = serde_json::to_vec(&op)?;
-        writer.write_all(&v)?;
-        let end = writer.seek(SeekFrom::End(0))?;
-        Ok(end - v.len() as u64)
+    fn write_active_marker(path: &Path, id: u32) -> Result<()> {
+        fs::write(path.join(ACTIVE_FILE_NAME), id.to_string())?;
+        Ok(())
     }
 
-    /// API to add a key-value pair to the Kv Log Store
-    pub fn set(&mut self, key: &str, value: &str) -> Result<u64> {
-        let entry = SeLogEntry {
-            key,
-            value: Some(value),
+    /// Writes the `kvls.active` marker for the current active segment. A
+    /// no-op for in-memory stores, which have no directory to write it in.
+    fn persist_active_marker(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            Self::write_active_marker(path, self.active.id)?;
+        }
+        Ok(())
+    }
+
+    fn write_header(path: &Path, codec: CodecId) -> Result<()> {
+        let mut bytes = Vec::with_capacity(6);
+        bytes.extend_from_slice(&FORMAT_MAGIC);
+        bytes.push(FORMAT_VERSION);
+        bytes.push(codec.to_byte());
+        fs::write(path.join(HEADER_FILE_NAME), bytes)?;
+        Ok(())
+    }
+
+    /// Migrates a store at `path` from an older on-disk format to the
+    /// current one. The pre-versioning layout (a single `kvls.ser` log
+    /// plus a one-byte `kvls.codec`) is replayed in full, and every live
+    /// key is rewritten into a fresh segmented store before the legacy
+    /// log is removed. A store already on the current format is left
+    /// untouched.
+    pub fn upgrade<F>(path: F) -> Result<()>
+    where
+        F: AsRef<Path> + AsRef<OsStr> + Clone,
+    {
+        let path = Path::new(&path).to_path_buf();
+        let (version, codec_id) = Self::read_header(&path)?
+            .ok_or_else(|| err_msg("No store found to upgrade"))?;
+        if version == FORMAT_VERSION {
+            return Ok(());
+        }
+        if version != LEGACY_FORMAT_VERSION {
+            return Err(err_msg(format!(
+                "Don't know how to upgrade from format version {}",
+                version
+            )));
+        }
+
+        let codec: Box<dyn Codec<K, V> + Send> = codec_id.codec();
+        let mut legacy_log = LogBackend::Disk(Self::open_disk_file(&path, LEGACY_LOG_FILE_NAME)?);
+
+        let mut pointers: HashMap<K, (u32, u64)> = HashMap::new();
+        Self::replay_into(codec.as_ref(), &mut legacy_log, 0, 0, &mut pointers)?;
+
+        let mut live: HashMap<K, V> = HashMap::new();
+        for (key, (_, pos)) in pointers {
+            let (entry, _) = Self::read_record(codec.as_ref(), &mut legacy_log, pos, true)?
+                .ok_or_else(|| err_msg("Corrupt log entry: short read"))?;
+            if let Some(value) = entry.value {
+                live.insert(key, value);
+            }
+        }
+        drop(legacy_log);
+
+        Self::write_header(&path, codec_id)?;
+
+        let mut store = Self::new_with(&path, codec_id)?;
+        for (key, value) in &live {
+            store.set(key, value)?;
+        }
+        fs::remove_file(path.join(LEGACY_LOG_FILE_NAME))?;
+        Ok(())
+    }
+
+    fn open_new_segment(&mut self) -> Result<Segment> {
+        let id = self.next_segment_id;
+        self.next_segment_id += 1;
+        let backend = match &self.path {
+            Some(path) => {
+                LogBackend::Disk(Self::open_disk_file(path, &Self::segment_filename(id))?)
+            }
+            None => LogBackend::Memory(Cursor::new(Vec::new())),
         };
-        let pos = Self::commit_operation(entry, &mut self.writer)?;
-        self.num_entries += 1;
-        Ok(pos)
+        Ok(Segment {
+            id,
+            backend,
+            num_entries: 0,
+        })
     }
 
-    /// API to remove a key if it exists in the Kv Log Store
-    pub fn remove(&mut self, key: &str) -> Result<()> {
-        let entry = SeLogEntry { key, value: None };
-        Self::commit_operation(entry, &mut self.writer)?;
-        self.num_entries += 1;
+    fn delete_segment_file(&self, id: u32) -> Result<()> {
+        if let Some(path) = &self.path {
+            fs::remove_file(path.join(Self::segment_filename(id)))?;
+        }
         Ok(())
     }
 
-    pub fn build_map(&mut self) -> Result<HashMap<String, u64>> {
-        let reader = self.reader.get_mut();
-        let mut map = HashMap::new();
-        let mut pos = reader.seek(SeekFrom::Start(0))?;
-        let mut stream = serde_json::Deserializer::from_reader(reader).into_iter();
-        while let Some(op) = stream.next() {
-            match op? {
+    /// Frame a single entry as `{ payload_len: u32, crc32: u32, payload }`
+    /// (all integers little-endian) and append it, returning the byte
+    /// offset of the start of the header within its segment. `payload` is
+    /// produced by the store's configured `Codec`.
+    fn commit_operation(
+        codec: &(dyn Codec<K, V> + Send),
+        op: SeLogEntry<K, V>,
+        backend: &mut LogBackend,
+    ) -> Result<u64> {
+        let payload = codec.encode(&op)?;
+        let crc = crc32::checksum_ieee(&payload);
+        let start = backend.seek(SeekFrom::End(0))?;
+        backend.write_all(&(payload.len() as u32).to_le_bytes())?;
+        backend.write_all(&crc.to_le_bytes())?;
+        backend.write_all(&payload)?;
+        Ok(start)
+    }
+
+    /// Seek to `pos` and read/validate the framed record there, decoding
+    /// the payload with `codec`. Returns the decoded entry plus the total
+    /// number of bytes the record occupies (header + payload). A short
+    /// read always yields `None`; a CRC mismatch yields `None` unless
+    /// `strict`, in which case it is an error.
+    fn read_record(
+        codec: &(dyn Codec<K, V> + Send),
+        backend: &mut LogBackend,
+        pos: u64,
+        strict: bool,
+    ) -> Result<Option<(DeLogEntry<K, V>, u64)>> {
+        backend.seek(SeekFrom::Start(pos))?;
+
+        let mut header = [0u8; RECORD_HEADER_LEN as usize];
+        if backend.read_exact(&mut header).is_err() {
+            return Ok(None);
+        }
+        let payload_len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let expected_crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+
+        // A flipped bit in `payload_len` can claim a payload far larger than
+        // what's actually left in the backend; treat that as a short read
+        // instead of allocating up to 4GiB on its say-so.
+        let remaining = backend.len()?.saturating_sub(pos + RECORD_HEADER_LEN);
+        if payload_len as u64 > remaining {
+            return Ok(None);
+        }
+
+        let mut payload = vec![0u8; payload_len];
+        if backend.read_exact(&mut payload).is_err() {
+            return Ok(None);
+        }
+        if crc32::checksum_ieee(&payload) != expected_crc {
+            if strict {
+                return Err(err_msg("Corrupt log entry: CRC mismatch"));
+            }
+            return Ok(None);
+        }
+        let entry = codec.decode(&payload)?;
+        Ok(Some((entry, RECORD_HEADER_LEN + payload_len as u64)))
+    }
+
+    /// Replay `backend` (belonging to segment `id`) from `start`, folding
+    /// entries into `map`. A torn write or bit-flip at the tail is treated
+    /// as the end of the valid log: the segment is truncated back to the
+    /// last good offset rather than erroring out. Returns the final offset
+    /// and the number of records read.
+    fn replay_into(
+        codec: &(dyn Codec<K, V> + Send),
+        backend: &mut LogBackend,
+        id: u32,
+        start: u64,
+        map: &mut HashMap<K, (u32, u64)>,
+    ) -> Result<(u64, usize)> {
+        let mut pos = start;
+        let mut entries = 0usize;
+        loop {
+            let (entry, record_len) = match Self::read_record(codec, backend, pos, false)? {
+                Some(record) => record,
+                None => break,
+            };
+            match entry {
                 DeLogEntry {
                     key,
                     value: Some(_),
                 } => {
-                    map.insert(key, pos);
+                    map.insert(key, (id, pos));
                 }
                 DeLogEntry { key, value: None } => {
                     map.remove(&key);
                 }
-            };
-            self.num_entries += 1;
-            pos = stream.byte_offset() as u64;
+            }
+            entries += 1;
+            pos += record_len;
         }
+        backend.set_len(pos)?;
+        backend.seek(SeekFrom::Start(pos))?;
+        Ok((pos, entries))
+    }
+
+    /// API to add a key-value pair to the Kv Log Store. Returns the
+    /// `(segment_id, offset)` pointer `kvmap` should remember.
+    pub fn set(&mut self, key: &K, value: &V) -> Result<(u32, u64)> {
+        let entry = SeLogEntry {
+            key,
+            value: Some(value),
+        };
+        let pos = Self::commit_operation(self.codec.as_ref(), entry, &mut self.active.backend)?;
+        self.active.num_entries += 1;
+        let seg_id = self.active.id;
+        self.maybe_roll_active()?;
+        Ok((seg_id, pos))
+    }
+
+    /// API to remove a key if it exists in the Kv Log Store
+    pub fn remove(&mut self, key: &K) -> Result<()> {
+        let entry = SeLogEntry { key, value: None };
+        Self::commit_operation(self.codec.as_ref(), entry, &mut self.active.backend)?;
+        self.active.num_entries += 1;
+        self.maybe_roll_active()?;
+        Ok(())
+    }
+
+    /// Closes the active segment and opens a fresh one once it has grown
+    /// past `segment_size_threshold`, so live writes never block on a
+    /// segment of unbounded size.
+    fn maybe_roll_active(&mut self) -> Result<()> {
+        let len = self.active.backend.len()?;
+        if len < self.segment_size_threshold {
+            return Ok(());
+        }
+        let new_active = self.open_new_segment()?;
+        let closed = std::mem::replace(&mut self.active, new_active);
+        self.segments.push(closed);
+        self.persist_active_marker()?;
+        Ok(())
+    }
+
+    /// Rebuild the in-memory index from a full replay of every segment, in
+    /// ascending id order (closed segments, then the active one).
+    pub fn build_map(&mut self) -> Result<HashMap<K, (u32, u64)>> {
+        let mut map = HashMap::new();
+        let codec = self.codec.as_ref();
+
+        for segment in self.segments.iter_mut() {
+            let (_, entries) =
+                Self::replay_into(codec, &mut segment.backend, segment.id, 0, &mut map)?;
+            segment.num_entries = entries;
+        }
+
+        let (_, entries) = Self::replay_into(
+            codec,
+            &mut self.active.backend,
+            self.active.id,
+            0,
+            &mut map,
+        )?;
+        self.active.num_entries = entries;
+
         Ok(map)
     }
 
-    pub fn get_at_offset(&self, key: &str, pos: u64) -> Result<String> {
-        let mut reader = self.reader.get_ref().try_clone()?;
-        reader.seek(SeekFrom::Start(pos))?;
-        let stream = serde_json::Deserializer::from_reader(reader).into_iter::<DeLogEntry>();
-        for op in stream {
-            let op = op?;
-            if op.key == key {
-                if let Some(value) = op.value {
-                    return Ok(value);
-                } else {
-                    return Err(err_msg("KV map out of sync with KV store"));
+    /// Rebuild the in-memory index, skipping a full replay when a valid
+    /// index snapshot (written after the last merge) is on disk: if the
+    /// segment layout it was taken against is unchanged and the active
+    /// segment has only grown since, only the new tail of the active
+    /// segment is replayed; otherwise this falls back to a full
+    /// `build_map`.
+    pub fn load_or_build_map(&mut self) -> Result<HashMap<K, (u32, u64)>> {
+        if let Some(snapshot) = self.read_index()? {
+            let active_len = self.active.backend.len()?;
+            let closed_ids: Vec<u32> = self.segments.iter().map(|s| s.id).collect();
+            if snapshot.active_id == self.active.id
+                && snapshot.closed_ids == closed_ids
+                && snapshot.active_len <= active_len
+            {
+                for segment in self.segments.iter_mut() {
+                    if let Some((_, count)) = snapshot
+                        .segment_entry_counts
+                        .iter()
+                        .find(|(id, _)| *id == segment.id)
+                    {
+                        segment.num_entries = *count;
+                    }
                 }
-            } else {
-                return Err(err_msg(format!(
-                    "Key mismatch in log store. Expected: {}. Found: {}",
-                    op.key, key
-                )));
+                self.active.num_entries = snapshot.active_entries;
+
+                let mut map = snapshot.map;
+                if snapshot.active_len < active_len {
+                    self.replay_active_tail(snapshot.active_len, &mut map)?;
+                }
+                return Ok(map);
             }
+            // Segment layout changed (or the active segment shrank) since
+            // the snapshot was taken, so it can no longer be trusted.
         }
-        panic!("Shouldn't have been here!")
+        self.build_map()
     }
 
-    fn compaction_analysis(&mut self, map_len: usize) -> bool {
-        if self.num_entries < self.max_entries {
-            return false;
-        } else if self.num_entries < 2 * map_len {
-            while self.num_entries > self.max_entries {
-                self.max_entries *= 2;
+    fn replay_active_tail(&mut self, start: u64, map: &mut HashMap<K, (u32, u64)>) -> Result<()> {
+        let codec = self.codec.as_ref();
+        let id = self.active.id;
+        let (_, entries) = Self::replay_into(codec, &mut self.active.backend, id, start, map)?;
+        self.active.num_entries += entries;
+        Ok(())
+    }
+
+    fn read_index(&self) -> Result<Option<IndexSnapshot<K>>> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let index_file = path.join(INDEX_FILE_NAME);
+        if !index_file.exists() {
+            return Ok(None);
+        }
+        let file = File::open(index_file)?;
+        Ok(Some(serde_json::from_reader(file)?))
+    }
+
+    /// Snapshot `map` plus enough segment bookkeeping to validate it later,
+    /// so a subsequent open can skip replaying the log. A no-op for
+    /// in-memory stores, which have no directory to snapshot into.
+    fn write_index(&self, map: &HashMap<K, (u32, u64)>) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let snapshot = IndexSnapshot {
+            active_id: self.active.id,
+            active_len: self.active.backend.len()?,
+            active_entries: self.active.num_entries,
+            closed_ids: self.segments.iter().map(|s| s.id).collect(),
+            segment_entry_counts: self.segments.iter().map(|s| (s.id, s.num_entries)).collect(),
+            map: map.clone(),
+        };
+        let file = File::create(path.join(INDEX_FILE_NAME))?;
+        serde_json::to_writer(file, &snapshot)?;
+        Ok(())
+    }
+
+    /// Read and validate the record for `key` at `(seg_id, pos)`. Unlike
+    /// `build_map`, a CRC mismatch here is an error rather than
+    /// end-of-log, since the pointer came from a trusted index entry.
+    pub fn get_at_offset(&mut self, key: &K, seg_id: u32, pos: u64) -> Result<V> {
+        let active_id = self.active.id;
+        let codec = self.codec.as_ref();
+        let backend = if seg_id == active_id {
+            &mut self.active.backend
+        } else {
+            &mut self
+                .segments
+                .iter_mut()
+                .find(|segment| segment.id == seg_id)
+                .ok_or_else(|| err_msg(format!("Unknown segment id: {}", seg_id)))?
+                .backend
+        };
+
+        let (op, _) = Self::read_record(codec, backend, pos, true)?
+            .ok_or_else(|| err_msg("Corrupt log entry: short read"))?;
+        if &op.key == key {
+            if let Some(value) = op.value {
+                return Ok(value);
             }
+            return Err(err_msg("KV map out of sync with KV store"));
+        }
+        Err(err_msg(format!(
+            "Key mismatch in log store. Expected: {:?}. Found: {:?}",
+            key, op.key
+        )))
+    }
+
+    /// Estimates reclaimable space as the fraction of records in closed
+    /// segments that `map` no longer points at (superseded or removed).
+    fn merge_analysis(&self, map: &HashMap<K, (u32, u64)>) -> bool {
+        let total_closed: usize = self.segments.iter().map(|s| s.num_entries).sum();
+        if total_closed == 0 {
             return false;
         }
-        return true;
+        let closed_ids: Vec<u32> = self.segments.iter().map(|s| s.id).collect();
+        let live_closed = map
+            .values()
+            .filter(|(seg_id, _)| closed_ids.contains(seg_id))
+            .count();
+        let dead = total_closed.saturating_sub(live_closed);
+        (dead as f64 / total_closed as f64) >= self.merge_ratio
     }
 
-    pub fn do_compaction(&mut self, map: &mut HashMap<String, u64>) -> Result<bool> {
-        if !self.compaction_analysis(map.len()) {
+    /// Merges the closed segments into fresh ones, dropping dead records,
+    /// and deletes the originals. The active segment is never touched, so
+    /// the live write path stays cheap even while a merge is running.
+    pub fn do_compaction(&mut self, map: &mut HashMap<K, (u32, u64)>) -> Result<bool> {
+        if !self.merge_analysis(map) {
             return Ok(false);
         }
 
         let start = std::time::Instant::now();
-        eprintln!("Num Entries Before Compaction : {}", self.num_entries);
+        eprintln!("Closed segments before merge: {}", self.segments.len());
+
+        let closed_ids: Vec<u32> = self.segments.iter().map(|s| s.id).collect();
+        let keys_to_merge: Vec<K> = map
+            .iter()
+            .filter(|(_, (seg_id, _))| closed_ids.contains(seg_id))
+            .map(|(key, _)| key.clone())
+            .collect();
 
-        let (_reader, mut writer) = Self::open_file_handles(&self.path, COMPACTION_FILE)?;
+        let mut merged = Vec::new();
+        let mut current = self.open_new_segment()?;
 
-        for (key, pos) in map.iter_mut() {
-            let value = self.get_at_offset(key, *pos)?;
+        for key in keys_to_merge {
+            let (seg_id, pos) = map[&key];
+            let value = self.get_at_offset(&key, seg_id, pos)?;
             let entry = SeLogEntry {
-                key,
+                key: &key,
                 value: Some(&value),
             };
-            *pos = Self::commit_operation(entry, &mut writer)?;
+            let new_pos =
+                Self::commit_operation(self.codec.as_ref(), entry, &mut current.backend)?;
+            current.num_entries += 1;
+            map.insert(key, (current.id, new_pos));
+
+            if current.backend.len()? >= self.segment_size_threshold {
+                merged.push(std::mem::replace(&mut current, self.open_new_segment()?));
+            }
+        }
+        if current.num_entries > 0 {
+            merged.push(current);
+        } else {
+            self.delete_segment_file(current.id)?;
         }
 
-        std::fs::rename(
-            self.path.join(COMPACTION_FILE),
-            self.path.join(LOG_FILE_NAME),
-        )?;
+        let old_segments = std::mem::replace(&mut self.segments, merged);
+        for segment in old_segments {
+            self.delete_segment_file(segment.id)?;
+        }
 
-        let (reader, writer) = Self::open_file_handles(&self.path, LOG_FILE_NAME)?;
-        self.reader = reader;
-        self.writer = writer;
-        self.num_entries = map.len();
+        self.write_index(map)?;
 
         eprintln!(
-            "Num Entries After  Compaction : {}. Time taken: {}ms",
-            self.num_entries,
+            "Closed segments after merge: {}. Time taken: {}ms",
+            self.segments.len(),
             start.elapsed().as_millis()
         );
         Ok(true)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::JsonCodec;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvls-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn corrupt_tail_is_recovered_by_truncation() {
+        let mut store: KvLogStore<String, String> = KvLogStore::new_in_memory();
+        store.set(&"a".to_owned(), &"1".to_owned()).unwrap();
+        store.set(&"b".to_owned(), &"2".to_owned()).unwrap();
+
+        // Simulate a torn write: chop a few bytes off the tail, landing
+        // mid-record for the second entry.
+        let len = store.active.backend.len().unwrap();
+        store.active.backend.set_len(len - 3).unwrap();
+
+        let map = store.build_map().unwrap();
+        assert!(map.contains_key("a"));
+        assert!(!map.contains_key("b"));
+    }
+
+    #[test]
+    fn segment_rollover_and_merge_preserves_live_keys() {
+        let mut store: KvLogStore<String, String> = KvLogStore::new_in_memory();
+        store.segment_size_threshold = 40;
+        store.merge_ratio = 0.1;
+
+        for i in 0..20 {
+            store
+                .set(&format!("key{}", i), &format!("val{}", i))
+                .unwrap();
+        }
+        for i in 0..10 {
+            store
+                .set(&format!("key{}", i), &format!("updated{}", i))
+                .unwrap();
+        }
+        assert!(
+            store.segments.len() > 1,
+            "expected writes past the threshold to roll the active segment"
+        );
+
+        let mut map = store.build_map().unwrap();
+        assert!(
+            store.do_compaction(&mut map).unwrap(),
+            "expected the dead first copies to trigger a merge"
+        );
+
+        for i in 0..20 {
+            let key = format!("key{}", i);
+            let &(seg_id, pos) = map.get(&key).unwrap();
+            let value = store.get_at_offset(&key, seg_id, pos).unwrap();
+            let expected = if i < 10 {
+                format!("updated{}", i)
+            } else {
+                format!("val{}", i)
+            };
+            assert_eq!(value, expected);
+        }
+    }
+
+    #[test]
+    fn active_segment_survives_restart_after_merge_without_rollover() {
+        let dir = temp_dir("active-stable");
+        let active_before;
+        {
+            let mut store: KvLogStore<String, String> =
+                KvLogStore::new_with(&dir, CodecId::Json).unwrap();
+            store.segment_size_threshold = 40;
+            store.merge_ratio = 0.1;
+
+            for i in 0..20 {
+                store
+                    .set(&format!("key{}", i), &format!("val{}", i))
+                    .unwrap();
+            }
+            for i in 0..10 {
+                store
+                    .set(&format!("key{}", i), &format!("updated{}", i))
+                    .unwrap();
+            }
+            active_before = store.active.id;
+
+            let mut map = store.build_map().unwrap();
+            assert!(store.do_compaction(&mut map).unwrap());
+            assert_eq!(
+                store.active.id, active_before,
+                "do_compaction must never touch the active segment"
+            );
+        }
+
+        // Reopen as a fresh process would, after the merge above assigned
+        // the merge output ids higher than the still-active segment.
+        let reopened: KvLogStore<String, String> =
+            KvLogStore::new_with(&dir, CodecId::Json).unwrap();
+        assert_eq!(
+            reopened.active.id, active_before,
+            "restart must not mistake a merge-output segment for the active one"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_does_not_leave_a_stray_empty_segment_when_nothing_to_carry_over() {
+        let dir = temp_dir("merge-empty");
+        let mut store: KvLogStore<String, String> = KvLogStore::new_with(&dir, CodecId::Json).unwrap();
+
+        // Force each write into its own closed segment first...
+        store.segment_size_threshold = 1;
+        store.set(&"a".to_owned(), &"1".to_owned()).unwrap();
+        store.set(&"b".to_owned(), &"2".to_owned()).unwrap();
+
+        // ...then raise the threshold so the overwrites below land in the
+        // active segment without rolling it over, leaving both closed
+        // segments entirely dead by the time the merge runs.
+        store.segment_size_threshold = 10_000;
+        store.merge_ratio = 0.1;
+        store.set(&"a".to_owned(), &"11".to_owned()).unwrap();
+        store.set(&"b".to_owned(), &"22".to_owned()).unwrap();
+
+        let mut map = store.build_map().unwrap();
+        assert!(store.do_compaction(&mut map).unwrap());
+
+        let seg_files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().ends_with(".seg"))
+            .collect();
+        assert_eq!(
+            seg_files.len(),
+            1,
+            "expected only the active segment file to remain, found {:?}",
+            seg_files
+                .iter()
+                .map(|e| e.file_name())
+                .collect::<Vec<_>>()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn upgrade_migrates_legacy_log_in_place() {
+        let dir = temp_dir("upgrade");
+
+        // Hand-write a pre-versioning store: a single length+CRC framed
+        // `kvls.ser` log plus the one-byte legacy `kvls.codec`, the way
+        // chunk0-2 through chunk0-6 wrote them (no magic, no segments).
+        let codec = JsonCodec;
+        let mut raw = Vec::new();
+        for (key, value) in [("a", Some("1")), ("b", Some("2")), ("b", None)] {
+            let key = key.to_owned();
+            let value = value.map(|v| v.to_owned());
+            let entry = SeLogEntry {
+                key: &key,
+                value: value.as_ref(),
+            };
+            let payload = codec.encode(&entry).unwrap();
+            let crc = crc32::checksum_ieee(&payload);
+            raw.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            raw.extend_from_slice(&crc.to_le_bytes());
+            raw.extend_from_slice(&payload);
+        }
+        fs::write(dir.join(LEGACY_LOG_FILE_NAME), &raw).unwrap();
+        fs::write(dir.join(HEADER_FILE_NAME), [CodecId::Json.to_byte()]).unwrap();
+
+        KvLogStore::<String, String>::upgrade(&dir).unwrap();
+        assert!(!dir.join(LEGACY_LOG_FILE_NAME).exists());
+
+        let mut store = KvLogStore::<String, String>::new_with(&dir, CodecId::Json).unwrap();
+        let map = store.load_or_build_map().unwrap();
+        assert_eq!(map.len(), 1);
+        let &(seg_id, pos) = map.get("a").unwrap();
+        assert_eq!(
+            store.get_at_offset(&"a".to_owned(), seg_id, pos).unwrap(),
+            "1"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,13 @@
 pub use crate::kvls::KvLogStore;
+use crate::CodecId;
 use crate::Result;
 use failure::err_msg;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::fmt::Debug;
+use std::hash::Hash;
 use std::path::Path;
 
 /// A KvStore is a type which holds a map of keys to values. Keys are unique
@@ -11,48 +16,84 @@ use std::path::Path;
 /// Exmaple usage:
 /// ```
 /// use kvs::KvStore;
-/// let mut kv = KvStore::new();
+/// let mut kv = KvStore::<String, String>::new_in_memory();
 /// kv.set("one".to_owned(), "number one".to_owned());
 /// assert_eq!(kv.get("one".to_owned()), Some("number one".to_owned()));
 /// assert_eq!(kv.get("two".to_owned()), None);
 /// kv.remove("one".to_owned());
 /// assert_eq!(kv.get("one".to_owned()), None);
 /// ```
-pub struct KvStore {
-    kvmap: HashMap<String, u64>,
-    kvlog: KvLogStore,
+pub struct KvStore<K, V> {
+    kvmap: HashMap<K, (u32, u64)>,
+    kvlog: KvLogStore<K, V>,
 }
 
-impl KvStore {
-    /// API to open the KvStore from a given path and return it
-    pub fn open<F>(path: F) -> Result<KvStore>
+impl<K, V> KvStore<K, V>
+where
+    K: Eq + Hash + Debug + Clone + Serialize + DeserializeOwned + 'static,
+    V: Serialize + DeserializeOwned + 'static,
+{
+    /// API to open the KvStore from a given path and return it, using the
+    /// JSON codec for newly created stores
+    pub fn open<F>(path: F) -> Result<KvStore<K, V>>
     where
         F: AsRef<Path> + AsRef<OsStr> + Clone,
     {
-        let mut kvlog = KvLogStore::new(path)?;
-        let kvmap = kvlog.build_map()?;
+        Self::open_with(path, CodecId::Json)
+    }
+
+    /// API to open the KvStore from a given path with a preferred codec for
+    /// newly created stores. Reopening an existing store always uses the
+    /// codec it was created with, regardless of `codec`.
+    pub fn open_with<F>(path: F, codec: CodecId) -> Result<KvStore<K, V>>
+    where
+        F: AsRef<Path> + AsRef<OsStr> + Clone,
+    {
+        let mut kvlog = KvLogStore::new_with(path, codec)?;
+        let kvmap = kvlog.load_or_build_map()?;
         Ok(KvStore { kvmap, kvlog })
     }
 
+    /// Opens an ephemeral KvStore backed by a growable in-memory buffer
+    /// instead of a log file on disk, for fast, isolated tests or embedding
+    /// the store where persistence isn't wanted.
+    pub fn new_in_memory() -> KvStore<K, V> {
+        KvStore {
+            kvmap: HashMap::new(),
+            kvlog: KvLogStore::new_in_memory(),
+        }
+    }
+
+    /// Migrates an on-disk store at `path` from an older format to the
+    /// current one, in place. No-op if it's already current. Unlike
+    /// `open`, this does not return a usable `KvStore`; reopen `path`
+    /// with `open` afterwards.
+    pub fn upgrade<F>(path: F) -> Result<()>
+    where
+        F: AsRef<Path> + AsRef<OsStr> + Clone,
+    {
+        KvLogStore::<K, V>::upgrade(path)
+    }
+
     /// API to add a key-value pair to the KvStore
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+    pub fn set(&mut self, key: K, value: V) -> Result<()> {
         self.kvlog.do_compaction(&mut self.kvmap)?;
-        let pos = self.kvlog.set(&key, &value)?;
-        self.kvmap.insert(key, pos);
+        let ptr = self.kvlog.set(&key, &value)?;
+        self.kvmap.insert(key, ptr);
         Ok(())
     }
 
     /// API to query if a key is present in the KvStore and return its value
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(pos) = self.kvmap.get(&key) {
-            let value = self.kvlog.get_at_offset(&key, *pos)?;
+    pub fn get(&mut self, key: K) -> Result<Option<V>> {
+        if let Some(&(seg_id, pos)) = self.kvmap.get(&key) {
+            let value = self.kvlog.get_at_offset(&key, seg_id, pos)?;
             return Ok(Some(value));
         }
         Ok(None)
     }
 
     /// API to remove a key if it exists in the KvStore
-    pub fn remove(&mut self, key: String) -> Result<()> {
+    pub fn remove(&mut self, key: K) -> Result<()> {
         self.kvlog.do_compaction(&mut self.kvmap)?;
         if !self.kvmap.contains_key(&key) {
             return Err(err_msg("Key not found"));
@@ -1,5 +1,10 @@
+mod backend;
+mod codec;
 mod kvcli;
+mod kvls;
 mod kvs;
+pub mod net;
+pub use crate::codec::CodecId;
 pub use crate::kvcli::KvCli;
 pub use crate::kvs::KvStore;
 use serde::{Deserialize, Serialize};
@@ -27,4 +32,15 @@ pub enum Operations {
         #[structopt(required = true)]
         key: String,
     },
+    /// migrate an on-disk store from an older format to the current one
+    Upgrade,
+}
+
+/// The wire response sent by `kvs-server` for an `Operations` request. Store
+/// errors (e.g. removing a missing key) are mapped to `Err` with the
+/// message the in-process CLI would have printed.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Ok(Option<String>),
+    Err(String),
 }
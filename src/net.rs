@@ -0,0 +1,37 @@
+use crate::Result;
+use failure::err_msg;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{Read, Write};
+
+/// Upper bound on a single frame's payload. A `Set` request or its value is
+/// never anywhere near this size; it exists so a corrupted or malicious
+/// length prefix can't make us allocate gigabytes before we've even
+/// validated the bytes behind it.
+const MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+/// Writes `msg` as a length-prefixed (`u32` little-endian length, then JSON
+/// payload) frame. This is the wire format shared by `kvs-server` and
+/// `kvs-client` for both requests and responses.
+pub fn send_message<T: Serialize>(mut stream: impl Write, msg: &T) -> Result<()> {
+    let bytes = serde_json::to_vec(msg)?;
+    stream.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    stream.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Reads one length-prefixed frame written by `send_message`.
+pub fn recv_message<T: DeserializeOwned>(mut stream: impl Read) -> Result<T> {
+    let mut len_buf = [0u8; 4];
+    stream.read_exact(&mut len_buf)?;
+    let len = u32::from_le_bytes(len_buf) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(err_msg(format!(
+            "Frame length {} exceeds max message size {}",
+            len, MAX_MESSAGE_LEN
+        )));
+    }
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf)?;
+    Ok(serde_json::from_slice(&buf)?)
+}